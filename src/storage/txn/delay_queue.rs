@@ -0,0 +1,212 @@
+// Copyright 2018 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! A small shared delay queue: a single background thread that wakes up at
+//! the nearest scheduled deadline and runs whichever task is due. Used to
+//! finish cooperative commands (like `Command::Pause`) without blocking a
+//! scheduler worker thread for the whole delay.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::storage::txn::process::panic_message;
+
+type Task = Box<dyn FnOnce() + Send>;
+
+struct Entry {
+    deadline: Instant,
+    task: Task,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+impl Eq for Entry {}
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Entry {
+    // `BinaryHeap` is a max-heap; reverse the comparison so the entry with
+    // the nearest deadline sorts to the top.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+struct State {
+    entries: BinaryHeap<Entry>,
+    stopped: bool,
+}
+
+/// A background thread that runs tasks once their deadline elapses.
+pub struct DelayQueue {
+    shared: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl DelayQueue {
+    pub fn new(thread_name: &str) -> Self {
+        let shared = Arc::new((
+            Mutex::new(State {
+                entries: BinaryHeap::new(),
+                stopped: false,
+            }),
+            Condvar::new(),
+        ));
+        let worker_shared = Arc::clone(&shared);
+        thread::Builder::new()
+            .name(thread_name.to_owned())
+            .spawn(move || run(worker_shared))
+            .unwrap();
+        DelayQueue { shared }
+    }
+
+    /// Runs `task` after `delay` elapses.
+    pub fn delay(&self, delay: Duration, task: Task) {
+        let (lock, cvar) = &*self.shared;
+        let mut state = lock.lock().unwrap();
+        if state.stopped {
+            return;
+        }
+        state.entries.push(Entry {
+            deadline: Instant::now() + delay,
+            task,
+        });
+        cvar.notify_one();
+    }
+
+    /// Stops the background thread from accepting further delays and runs
+    /// every task still pending, right now, instead of dropping them. A
+    /// `Command::Pause` waiting in the queue at shutdown time must still get
+    /// its `Msg::WriteFinished` posted so its storage callback fires exactly
+    /// once, the same invariant `process_by_worker` upholds for panics.
+    pub fn shutdown(&self) {
+        let (lock, cvar) = &*self.shared;
+        let mut state = lock.lock().unwrap();
+        state.stopped = true;
+        let pending = mem::replace(&mut state.entries, BinaryHeap::new());
+        drop(state);
+        cvar.notify_one();
+
+        for entry in pending {
+            run_task(entry.task);
+        }
+    }
+}
+
+/// Runs a due task, converting a panic into a log message instead of taking
+/// down the (single, shared) delay queue thread with it — a panicking
+/// completion must not leave every other pending `Command::Pause` stuck in
+/// the heap forever.
+fn run_task(task: Task) {
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(task)) {
+        warn!("delay queue task panicked"; "err" => %panic_message(payload));
+    }
+}
+
+fn run(shared: Arc<(Mutex<State>, Condvar)>) {
+    let (lock, cvar) = &*shared;
+    let mut guard = lock.lock().unwrap();
+    loop {
+        if guard.stopped {
+            return;
+        }
+        match guard.entries.peek() {
+            None => {
+                guard = cvar.wait(guard).unwrap();
+            }
+            Some(next) => {
+                let now = Instant::now();
+                if next.deadline <= now {
+                    let entry = guard.entries.pop().unwrap();
+                    drop(guard);
+                    run_task(entry.task);
+                    guard = lock.lock().unwrap();
+                } else {
+                    let (g, _timed_out) = cvar.wait_timeout(guard, next.deadline - now).unwrap();
+                    guard = g;
+                }
+            }
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    /// The delay queue shared by every `Command::Pause` (and, potentially,
+    /// future cooperative commands) so a pause doesn't need its own
+    /// dedicated OS thread.
+    pub static ref GLOBAL_DELAY_QUEUE: DelayQueue = DelayQueue::new("sched-delay-queue");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_runs_tasks_in_deadline_order() {
+        let queue = DelayQueue::new("test-delay-queue-order");
+        let (tx, rx) = mpsc::channel();
+
+        // Enqueue out of deadline order; the queue should still run them
+        // nearest-deadline-first.
+        let tx2 = tx.clone();
+        queue.delay(Duration::from_millis(60), Box::new(move || tx2.send(2).unwrap()));
+        let tx1 = tx.clone();
+        queue.delay(Duration::from_millis(10), Box::new(move || tx1.send(1).unwrap()));
+        let tx3 = tx.clone();
+        queue.delay(Duration::from_millis(110), Box::new(move || tx3.send(3).unwrap()));
+
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), 1);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), 2);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_task_does_not_run_before_its_deadline() {
+        let queue = DelayQueue::new("test-delay-queue-delay");
+        let (tx, rx) = mpsc::channel();
+
+        queue.delay(Duration::from_millis(100), Box::new(move || tx.send(()).unwrap()));
+
+        // Nothing should have run yet, well before the deadline.
+        assert!(rx.recv_timeout(Duration::from_millis(30)).is_err());
+        // But it does run once the deadline has passed.
+        assert!(rx.recv_timeout(Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn test_shutdown_drains_pending_tasks_instead_of_dropping_them() {
+        let queue = DelayQueue::new("test-delay-queue-shutdown");
+        let (tx, rx) = mpsc::channel();
+
+        let tx1 = tx.clone();
+        queue.delay(Duration::from_secs(60), Box::new(move || tx1.send(1).unwrap()));
+        let tx2 = tx.clone();
+        queue.delay(Duration::from_secs(60), Box::new(move || tx2.send(2).unwrap()));
+
+        // Neither task's deadline has passed, but shutdown must still run
+        // them now rather than silently dropping them.
+        queue.shutdown();
+
+        let mut got = vec![
+            rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+            rx.recv_timeout(Duration::from_secs(1)).unwrap(),
+        ];
+        got.sort();
+        assert_eq!(got, vec![1, 2]);
+
+        // Once stopped, new delays are silently ignored rather than queued
+        // forever behind a thread that no longer runs anything.
+        let tx3 = tx;
+        queue.delay(Duration::from_millis(0), Box::new(move || tx3.send(3).unwrap()));
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+// Copyright 2018 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Transaction related types and errors.
+//!
+//! `process.rs` does the actual transaction processing, running commands on
+//! the worker pools in `sched_pool.rs` off the raft propose thread and
+//! reporting each result, as one of the `Msg`s defined in `scheduler.rs`,
+//! back to the scheduler that holds the command's latches.
+
+mod delay_queue;
+pub mod process;
+pub mod sched_pool;
+pub mod scheduler;
+
+pub use self::process::{
+    execute_callback, Executor, MsgScheduler, ProcessResult, Task, RESOLVE_LOCK_BATCH_SIZE,
+};
+pub use self::sched_pool::{SchedPool, SchedRuntime};
+pub use self::scheduler::Msg;
+
+/// Stops accepting new deferred completions (e.g. from `Command::Pause`) and
+/// runs whatever is still pending. Call this from the store's shutdown
+/// sequence, alongside stopping the read/write `SchedRuntime`s, so no
+/// in-flight command is left without its storage callback firing.
+pub fn shutdown() {
+    self::delay_queue::GLOBAL_DELAY_QUEUE.shutdown();
+}
+
+use std::io::Error as IoError;
+
+use crate::storage::kv::Error as EngineError;
+use crate::storage::mvcc::Error as MvccError;
+use crate::storage::Error as StorageError;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Engine(err: EngineError) {
+            from()
+            cause(err)
+            description(err.description())
+        }
+        Mvcc(err: MvccError) {
+            from()
+            cause(err)
+            description(err.description())
+        }
+        Io(err: IoError) {
+            from()
+            cause(err)
+            description(err.description())
+        }
+        InvalidTxnTso { start_ts: u64, commit_ts: u64 } {
+            description("Invalid transaction tso")
+            display("Invalid transaction tso with start_ts:{},commit_ts:{}",
+                        start_ts, commit_ts)
+        }
+        // A worker-thread future panicked while processing a command. Carries
+        // enough context to log the failure without re-deriving it from the cid.
+        Panic(cid: u64, tag: &'static str, message: String) {
+            description("command processing panicked")
+            display("command {} ({}) panicked: {}", cid, tag, message)
+        }
+        // The scheduler's worker pool is already running its budgeted number
+        // of in-flight commands for this priority; the caller should back off
+        // and retry rather than queue indefinitely.
+        SchedTooBusy {
+            description("scheduler is too busy")
+            display("scheduler is too busy")
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl From<Error> for StorageError {
+    fn from(err: Error) -> StorageError {
+        match err {
+            Error::Engine(e) => StorageError::from(e),
+            Error::Mvcc(e) => StorageError::from(e),
+            Error::InvalidTxnTso {
+                start_ts,
+                commit_ts,
+            } => StorageError::InvalidTxnTso {
+                start_ts,
+                commit_ts,
+            },
+            e => StorageError::Other(Box::new(e)),
+        }
+    }
+}
@@ -1,10 +1,10 @@
 // Copyright 2018 TiKV Project Authors. Licensed under Apache-2.0.
 
 use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
 use std::time::Duration;
-use std::{mem, thread, u64};
+use std::{mem, u64};
 
-use futures::future;
 use kvproto::kvrpcpb::{CommandPri, Context, LockInfo};
 
 use crate::storage::kv::with_tls_engine;
@@ -14,7 +14,9 @@ use crate::storage::mvcc::{
     Error as MvccError, Lock as MvccLock, MvccReader, MvccTxn, ReleasedLock, Write,
     MAX_TXN_WRITE_SIZE,
 };
-use crate::storage::txn::{sched_pool::*, scheduler::Msg, Error, Result};
+use crate::storage::txn::{
+    delay_queue::GLOBAL_DELAY_QUEUE, sched_pool::*, scheduler::Msg, Error, Result,
+};
 use crate::storage::{
     metrics::*, Command, Engine, Error as StorageError, Key, MvccInfo, Result as StorageResult,
     ScanMode, Snapshot, Statistics, StorageCb, Value,
@@ -116,9 +118,11 @@ pub trait MsgScheduler: Clone + Send + 'static {
     fn on_msg(&self, task: Msg);
 }
 
-pub struct Executor<E: Engine, S: MsgScheduler, L: LockMgr> {
-    // We put time consuming tasks to the thread pool.
-    sched_pool: Option<SchedPool>,
+pub struct Executor<E: Engine, S: MsgScheduler, L: LockMgr, R: SchedRuntime = SchedPool> {
+    // Read commands (long, concurrent MVCC scans) run on this runtime...
+    read_runtime: Option<R>,
+    // ...while write commands (latch-holding, feeding the commit pipeline) run on this one.
+    write_runtime: Option<R>,
     // And the tasks completes we post a completion to the `Scheduler`.
     scheduler: Option<S>,
     // If the task releases some locks, we wake up waiters waiting for them.
@@ -127,22 +131,27 @@ pub struct Executor<E: Engine, S: MsgScheduler, L: LockMgr> {
     _phantom: PhantomData<E>,
 }
 
-impl<E: Engine, S: MsgScheduler, L: LockMgr> Executor<E, S, L> {
-    pub fn new(scheduler: S, pool: SchedPool, lock_mgr: Option<L>) -> Self {
+impl<E: Engine, S: MsgScheduler, L: LockMgr, R: SchedRuntime> Executor<E, S, L, R> {
+    pub fn new(scheduler: S, read_runtime: R, write_runtime: R, lock_mgr: Option<L>) -> Self {
         Executor {
-            sched_pool: Some(pool),
+            read_runtime: Some(read_runtime),
+            write_runtime: Some(write_runtime),
             scheduler: Some(scheduler),
             lock_mgr,
             _phantom: Default::default(),
         }
     }
 
-    fn take_pool(&mut self) -> SchedPool {
-        self.sched_pool.take().unwrap()
+    fn take_write_runtime(&mut self) -> R {
+        self.write_runtime.take().unwrap()
     }
 
-    fn clone_pool(&mut self) -> SchedPool {
-        self.sched_pool.clone().unwrap()
+    fn runtime_for(&self, readonly: bool) -> R {
+        if readonly {
+            self.read_runtime.clone().unwrap()
+        } else {
+            self.write_runtime.clone().unwrap()
+        }
     }
 
     fn take_scheduler(&mut self) -> S {
@@ -174,16 +183,12 @@ impl<E: Engine, S: MsgScheduler, L: LockMgr> Executor<E, S, L> {
                     .inc();
 
                 info!("get snapshot failed"; "cid" => task.cid, "err" => ?err);
-                self.take_pool().pool.spawn(move || {
-                    notify_scheduler(
-                        self.take_scheduler(),
-                        Msg::FinishedWithErr {
-                            cid: task.cid,
-                            err: Error::from(err),
-                            tag: task.tag,
-                        },
-                    );
-                    future::ok::<_, ()>(())
+                let runtime = self.runtime_for(task.cmd.readonly());
+                let scheduler = self.take_scheduler();
+                runtime.dispatch(scheduler, move || Msg::FinishedWithErr {
+                    cid: task.cid,
+                    err: Error::from(err),
+                    tag: task.tag,
                 });
             }
         }
@@ -202,9 +207,34 @@ impl<E: Engine, S: MsgScheduler, L: LockMgr> Executor<E, S, L> {
         if let Some(term) = cb_ctx.term {
             task.cmd.mut_context().set_term(term);
         }
-        let sched_pool = self.clone_pool();
         let readonly = task.cmd.readonly();
-        sched_pool.pool.spawn(move || {
+        let runtime = self.runtime_for(readonly);
+        let cid = task.cid;
+        let pri = task.priority();
+
+        if !runtime.try_acquire(pri) {
+            SCHED_STAGE_COUNTER_VEC
+                .with_label_values(&[tag, "too_busy"])
+                .inc();
+            warn!("scheduler runtime is too busy, rejecting command"; "cid" => cid, "tag" => tag);
+            notify_scheduler(
+                self.take_scheduler(),
+                Msg::FinishedWithErr {
+                    cid,
+                    err: Error::SchedTooBusy,
+                    tag,
+                },
+            );
+            return;
+        }
+
+        // Keep a handle to the scheduler around: if the command processing
+        // below panics, `self` is poisoned by the unwind and we can no longer
+        // reach the scheduler through it, but the task's latches still need
+        // to be released and its callback still needs to fire.
+        let scheduler_on_panic = self.scheduler.clone();
+        let runtime_for_release = runtime.clone();
+        runtime.spawn(Box::new(move || {
             fail_point!("scheduler_async_snapshot_finish");
 
             let read_duration = Instant::now_coarse();
@@ -213,23 +243,30 @@ impl<E: Engine, S: MsgScheduler, L: LockMgr> Executor<E, S, L> {
             let ts = task.ts;
             let timer = SlowTimer::new();
 
-            let statistics = if readonly {
-                self.process_read(snapshot, task)
-            } else {
-                with_tls_engine(|engine| self.process_write(engine, snapshot, task))
-            };
-            tls_add_statistics(tag, &statistics);
-            slow_log!(
-                timer,
-                "[region {}] scheduler handle command: {}, ts: {}",
-                region_id,
-                tag,
-                ts
-            );
+            let mut executor = self;
+            let statistics = guard_against_panic(cid, tag, scheduler_on_panic, move || {
+                if readonly {
+                    executor.process_read(snapshot, task)
+                } else {
+                    with_tls_engine(|engine| executor.process_write(engine, snapshot, task))
+                }
+            });
+
+            if let Some(statistics) = statistics {
+                tls_add_statistics(tag, &statistics);
+                slow_log!(
+                    timer,
+                    "[region {}] scheduler handle command: {}, ts: {}",
+                    region_id,
+                    tag,
+                    ts
+                );
+
+                tls_collect_read_duration(tag, read_duration.elapsed());
+            }
 
-            tls_collect_read_duration(tag, read_duration.elapsed());
-            future::ok::<_, ()>(())
-        });
+            runtime_for_release.release(pri);
+        }));
     }
 
     /// Processes a read command within a worker thread, then posts `ReadFinished` message back to the
@@ -267,12 +304,38 @@ impl<E: Engine, S: MsgScheduler, L: LockMgr> Executor<E, S, L> {
                 rows,
                 pr,
                 lock_info,
+                delay,
             }) => {
                 SCHED_STAGE_COUNTER_VEC
                     .with_label_values(&[tag, "write"])
                     .inc();
 
-                if let Some(lock_info) = lock_info {
+                if let Some(delay) = delay {
+                    // Cooperative yield (e.g. `Command::Pause`): there's
+                    // nothing to write, so hand the completion to the shared
+                    // delay queue instead of delivering it now, freeing this
+                    // worker thread immediately instead of parking it for
+                    // `delay`. Once the delay queue's single background
+                    // thread fires, it hands the actual delivery back to
+                    // `write_runtime` via `dispatch` rather than calling
+                    // `scheduler.on_msg` itself, so that a slow `on_msg` (a
+                    // latch release that wakes the next queued command)
+                    // can't serialize behind every other pending `Pause`.
+                    let scheduler = scheduler.clone();
+                    let write_runtime = self.take_write_runtime();
+                    GLOBAL_DELAY_QUEUE.delay(
+                        delay,
+                        Box::new(move || {
+                            write_runtime.dispatch(scheduler, move || Msg::WriteFinished {
+                                cid,
+                                pr,
+                                result: Ok(()),
+                                tag,
+                            });
+                        }),
+                    );
+                    return statistics;
+                } else if let Some(lock_info) = lock_info {
                     let (lock, is_first_lock, wait_timeout) = lock_info;
                     Msg::WaitForLock {
                         cid,
@@ -291,24 +354,20 @@ impl<E: Engine, S: MsgScheduler, L: LockMgr> Executor<E, S, L> {
                     }
                 } else {
                     let sched = scheduler.clone();
-                    let sched_pool = self.take_pool();
+                    let write_runtime = self.take_write_runtime();
                     // The callback to receive async results of write prepare from the storage engine.
                     let engine_cb = Box::new(move |(_, result)| {
-                        sched_pool.pool.spawn(move || {
-                            notify_scheduler(
-                                sched,
-                                Msg::WriteFinished {
-                                    cid,
-                                    pr,
-                                    result,
-                                    tag,
-                                },
-                            );
+                        write_runtime.dispatch(sched, move || {
                             KV_COMMAND_KEYWRITE_HISTOGRAM_VEC
                                 .with_label_values(&[tag])
                                 .observe(rows as f64);
-                            future::ok::<_, ()>(())
-                        })
+                            Msg::WriteFinished {
+                                cid,
+                                pr,
+                                result,
+                                tag,
+                            }
+                        });
                     });
 
                     if let Err(e) = engine.async_write(&ctx, to_be_write, engine_cb) {
@@ -515,6 +574,9 @@ struct WriteResult {
     pr: ProcessResult,
     // (lock, is_first_lock, wait_timeout)
     lock_info: Option<(lock_manager::Lock, bool, i64)>,
+    // If set, the command's completion should be delivered after this delay
+    // instead of immediately (e.g. `Command::Pause`).
+    delay: Option<Duration>,
 }
 
 fn process_write_impl<S: Snapshot, L: LockMgr>(
@@ -523,7 +585,7 @@ fn process_write_impl<S: Snapshot, L: LockMgr>(
     lock_mgr: Option<L>,
     statistics: &mut Statistics,
 ) -> Result<WriteResult> {
-    let (pr, to_be_write, rows, ctx, lock_info) = match cmd {
+    let (pr, to_be_write, rows, ctx, lock_info, delay) = match cmd {
         Command::Prewrite {
             ctx,
             mutations,
@@ -569,11 +631,11 @@ fn process_write_impl<S: Snapshot, L: LockMgr>(
             if locks.is_empty() {
                 let pr = ProcessResult::MultiRes { results: vec![] };
                 let modifies = txn.into_modifies();
-                (pr, modifies, rows, ctx, None)
+                (pr, modifies, rows, ctx, None, None)
             } else {
                 // Skip write stage if some keys are locked.
                 let pr = ProcessResult::MultiRes { results: locks };
-                (pr, vec![], 0, ctx, None)
+                (pr, vec![], 0, ctx, None, None)
             }
         }
         Command::AcquirePessimisticLock {
@@ -603,13 +665,13 @@ fn process_write_impl<S: Snapshot, L: LockMgr>(
             if locks.is_empty() {
                 let pr = ProcessResult::MultiRes { results: vec![] };
                 let modifies = txn.into_modifies();
-                (pr, modifies, rows, ctx, None)
+                (pr, modifies, rows, ctx, None, None)
             } else {
                 let lock = lock_manager::extract_lock_from_result(&locks[0]);
                 let pr = ProcessResult::MultiRes { results: locks };
                 let lock_info = Some((lock, options.is_first_lock, options.wait_timeout));
                 // Wait for lock released
-                (pr, vec![], 0, ctx, lock_info)
+                (pr, vec![], 0, ctx, lock_info, None)
             }
         }
         Command::Commit {
@@ -635,7 +697,7 @@ fn process_write_impl<S: Snapshot, L: LockMgr>(
             released_locks.wake_up(lock_mgr.as_ref());
 
             statistics.add(&txn.take_statistics());
-            (ProcessResult::Res, txn.into_modifies(), rows, ctx, None)
+            (ProcessResult::Res, txn.into_modifies(), rows, ctx, None, None)
         }
         Command::Cleanup {
             ctx,
@@ -651,7 +713,7 @@ fn process_write_impl<S: Snapshot, L: LockMgr>(
             released_locks.wake_up(lock_mgr.as_ref());
 
             statistics.add(&txn.take_statistics());
-            (ProcessResult::Res, txn.into_modifies(), 1, ctx, None)
+            (ProcessResult::Res, txn.into_modifies(), 1, ctx, None, None)
         }
         Command::Rollback {
             ctx,
@@ -669,7 +731,7 @@ fn process_write_impl<S: Snapshot, L: LockMgr>(
             released_locks.wake_up(lock_mgr.as_ref());
 
             statistics.add(&txn.take_statistics());
-            (ProcessResult::Res, txn.into_modifies(), rows, ctx, None)
+            (ProcessResult::Res, txn.into_modifies(), rows, ctx, None, None)
         }
         Command::PessimisticRollback {
             ctx,
@@ -694,6 +756,7 @@ fn process_write_impl<S: Snapshot, L: LockMgr>(
                 rows,
                 ctx,
                 None,
+                None,
             )
         }
         Command::ResolveLock {
@@ -752,7 +815,7 @@ fn process_write_impl<S: Snapshot, L: LockMgr>(
                 }
             };
 
-            (pr, txn.into_modifies(), rows, ctx, None)
+            (pr, txn.into_modifies(), rows, ctx, None, None)
         }
         Command::ResolveLockLite {
             ctx,
@@ -776,7 +839,7 @@ fn process_write_impl<S: Snapshot, L: LockMgr>(
             released_locks.wake_up(lock_mgr.as_ref());
 
             statistics.add(&txn.take_statistics());
-            (ProcessResult::Res, txn.into_modifies(), rows, ctx, None)
+            (ProcessResult::Res, txn.into_modifies(), rows, ctx, None, None)
         }
         Command::TxnHeartBeat {
             ctx,
@@ -793,12 +856,21 @@ fn process_write_impl<S: Snapshot, L: LockMgr>(
                 lock_ttl,
                 commit_ts: 0,
             };
-            (pr, txn.into_modifies(), 1, ctx, None)
-        }
-        Command::Pause { ctx, duration, .. } => {
-            thread::sleep(Duration::from_millis(duration));
-            (ProcessResult::Res, vec![], 0, ctx, None)
+            (pr, txn.into_modifies(), 1, ctx, None, None)
         }
+        // `Pause` has nothing to write; it just needs its completion delayed
+        // by `duration`. Yield the (empty) result immediately and let
+        // `process_write` register the deferred completion on the shared
+        // delay queue instead of parking this worker thread in
+        // `thread::sleep`.
+        Command::Pause { ctx, duration, .. } => (
+            ProcessResult::Res,
+            vec![],
+            0,
+            ctx,
+            None,
+            Some(Duration::from_millis(duration)),
+        ),
         _ => panic!("unsupported write command"),
     };
 
@@ -808,6 +880,7 @@ fn process_write_impl<S: Snapshot, L: LockMgr>(
         rows,
         pr,
         lock_info,
+        delay,
     })
 }
 
@@ -815,6 +888,53 @@ pub fn notify_scheduler<S: MsgScheduler>(scheduler: S, msg: Msg) {
     scheduler.on_msg(msg);
 }
 
+/// Runs `f`, catching any panic and reporting it to `scheduler_on_panic` as a
+/// `FinishedWithErr(Error::Panic)` message instead of letting it propagate —
+/// `f`'s owning `Executor` is poisoned by the unwind, but the command's
+/// latches and storage callback still need releasing exactly once. Returns
+/// `f`'s result, or `None` if it panicked.
+fn guard_against_panic<S: MsgScheduler>(
+    cid: u64,
+    tag: &'static str,
+    scheduler_on_panic: Option<S>,
+    f: impl FnOnce() -> Statistics,
+) -> Option<Statistics> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(statistics) => Some(statistics),
+        Err(payload) => {
+            let message = panic_message(payload);
+            warn!(
+                "command panicked while processing, releasing latches";
+                "cid" => cid, "tag" => tag, "err" => %message
+            );
+            if let Some(scheduler) = scheduler_on_panic {
+                notify_scheduler(
+                    scheduler,
+                    Msg::FinishedWithErr {
+                        cid,
+                        err: Error::Panic(cid, tag, message),
+                        tag,
+                    },
+                );
+            }
+            None
+        }
+    }
+}
+
+/// Extracts a human-readable message out of a `catch_unwind` payload, falling
+/// back to a generic description for panics whose payload is neither a `&str`
+/// nor a `String` (e.g. one raised with `std::panic::panic_any(42)`).
+pub(crate) fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&'static str>() {
+        (*s).to_owned()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "worker thread panicked with an unknown payload".to_owned()
+    }
+}
+
 // Make clippy happy.
 type MultipleReturnValue = (Option<MvccLock>, Vec<(u64, Write)>, Vec<(u64, Value)>);
 
@@ -841,3 +961,57 @@ fn find_mvcc_infos_by_key<S: Snapshot>(
     }
     Ok((lock, writes, values))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[derive(Clone)]
+    struct RecordingScheduler {
+        sender: mpsc::Sender<Msg>,
+    }
+
+    impl MsgScheduler for RecordingScheduler {
+        fn on_msg(&self, task: Msg) {
+            self.sender.send(task).unwrap();
+        }
+    }
+
+    // `guard_against_panic` is the exact catch_unwind wrapper
+    // `process_by_worker` spawns every command closure through; drive it
+    // directly with a closure that panics so a regression that drops the
+    // `catch_unwind` (or stops posting `FinishedWithErr`) fails this test
+    // instead of silently letting commands hang their latches forever.
+    #[test]
+    fn test_panicking_command_reports_panic_error_instead_of_hanging() {
+        let (tx, rx) = mpsc::channel();
+        let scheduler = RecordingScheduler { sender: tx };
+        let cid = 42;
+        let tag = "test";
+
+        let result = guard_against_panic(cid, tag, Some(scheduler), || -> Statistics {
+            panic!("boom");
+        });
+
+        assert!(result.is_none());
+
+        match rx.recv_timeout(Duration::from_secs(1)).unwrap() {
+            Msg::FinishedWithErr {
+                cid: got_cid,
+                err,
+                tag: got_tag,
+            } => {
+                assert_eq!(got_cid, cid);
+                assert_eq!(got_tag, tag);
+                assert!(match err {
+                    Error::Panic(panic_cid, panic_tag, ref message) => {
+                        panic_cid == cid && panic_tag == tag && message.contains("boom")
+                    }
+                    _ => false,
+                });
+            }
+            _ => panic!("expected a FinishedWithErr message"),
+        }
+    }
+}
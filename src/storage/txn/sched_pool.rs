@@ -0,0 +1,177 @@
+// Copyright 2018 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Pluggable execution backends for scheduler command futures.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::future;
+use kvproto::kvrpcpb::CommandPri;
+use tikv_util::futurepool::{Builder, FuturePool};
+
+use crate::storage::txn::process::MsgScheduler;
+use crate::storage::txn::scheduler::Msg;
+
+// Default in-flight command budgets, expressed as a multiple of the pool's
+// worker count. High-priority commands (typically small, latency-sensitive
+// transactions) get their own, larger budget so a flood of low-priority
+// scans can't starve them out of the queue entirely.
+const DEFAULT_NORMAL_CAPACITY_FACTOR: usize = 50;
+const DEFAULT_HIGH_CAPACITY_FACTOR: usize = 100;
+
+/// A pluggable execution backend for scheduler command futures.
+///
+/// `Executor` holds one `SchedRuntime` for read commands and one for write
+/// commands, so a deployment can give long, concurrent MVCC scans a runtime
+/// tuned differently from the smaller pool feeding the latch/commit write
+/// pipeline, or swap in an entirely different scheduling strategy (e.g. a
+/// priority-queue executor) without touching command-processing logic.
+pub trait SchedRuntime: Clone + Send + 'static {
+    /// Reserves a slot for a command of the given priority. Returns `false`
+    /// (reserving nothing) if the runtime's queue is already at capacity for
+    /// that priority.
+    fn try_acquire(&self, pri: CommandPri) -> bool;
+
+    /// Releases a slot previously reserved by `try_acquire` for the same
+    /// priority.
+    fn release(&self, pri: CommandPri);
+
+    /// Spawns a unit of work onto the runtime.
+    fn spawn(&self, task: Box<dyn FnOnce() + Send>);
+
+    /// The number of commands currently in flight, for monitoring.
+    fn queue_depth(&self) -> usize;
+
+    /// Shuts the runtime down; existing in-flight commands are left to
+    /// finish. `SchedPool`'s implementation is a no-op and does not stop it
+    /// from accepting new work.
+    fn shutdown(&self);
+
+    /// Runs `task` on the runtime and delivers whatever `Msg` it produces to
+    /// `scheduler`. This is the write path's actual entry point into the
+    /// runtime ("run this command, post its result") so callers don't each
+    /// have to close over the scheduler and re-implement the hand-off, and
+    /// a runtime backed by a different execution model only has to get
+    /// `spawn` right to support it.
+    fn dispatch<S: MsgScheduler>(&self, scheduler: S, task: impl FnOnce() -> Msg + Send + 'static) {
+        self.spawn(Box::new(move || {
+            scheduler.on_msg(task());
+        }));
+    }
+}
+
+/// The default `SchedRuntime`: a shared OS-thread pool, with one bounded
+/// in-flight budget for `CommandPri::High` and a second one shared by all
+/// other priorities.
+#[derive(Clone)]
+pub struct SchedPool {
+    pub pool: FuturePool,
+    normal_capacity: usize,
+    high_capacity: usize,
+    running_normal: Arc<AtomicUsize>,
+    running_high: Arc<AtomicUsize>,
+}
+
+impl SchedPool {
+    pub fn new(pool_size: usize, name_prefix: &str) -> Self {
+        Self::with_capacity(
+            pool_size,
+            name_prefix,
+            pool_size * DEFAULT_NORMAL_CAPACITY_FACTOR,
+            pool_size * DEFAULT_HIGH_CAPACITY_FACTOR,
+        )
+    }
+
+    pub fn with_capacity(
+        pool_size: usize,
+        name_prefix: &str,
+        normal_capacity: usize,
+        high_capacity: usize,
+    ) -> Self {
+        let pool = Builder::new()
+            .name_prefix(name_prefix)
+            .pool_size(pool_size)
+            .build();
+        SchedPool {
+            pool,
+            normal_capacity,
+            high_capacity,
+            running_normal: Arc::new(AtomicUsize::new(0)),
+            running_high: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn counter_and_capacity(&self, pri: CommandPri) -> (&AtomicUsize, usize) {
+        if pri == CommandPri::High {
+            (&self.running_high, self.high_capacity)
+        } else {
+            (&self.running_normal, self.normal_capacity)
+        }
+    }
+}
+
+impl SchedRuntime for SchedPool {
+    fn try_acquire(&self, pri: CommandPri) -> bool {
+        let (running, capacity) = self.counter_and_capacity(pri);
+        let before = running.fetch_add(1, Ordering::SeqCst);
+        if before >= capacity {
+            running.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    fn release(&self, pri: CommandPri) {
+        let (running, _) = self.counter_and_capacity(pri);
+        running.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn spawn(&self, task: Box<dyn FnOnce() + Send>) {
+        self.pool.spawn(move || {
+            task();
+            future::ok::<_, ()>(())
+        });
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.running_normal.load(Ordering::SeqCst) + self.running_high.load(Ordering::SeqCst)
+    }
+
+    fn shutdown(&self) {
+        // A no-op: `spawn` keeps accepting work after this call. The
+        // underlying `FuturePool` only stops once the last clone of this
+        // pool is dropped.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The whole point of separate per-priority budgets is that high-priority
+    // commands get more headroom than normal ones; this pins that ordering so
+    // the two constants can't be silently swapped again (as they were between
+    // this request's commit and its fix-up).
+    #[test]
+    fn test_high_priority_default_budget_exceeds_normal() {
+        assert!(DEFAULT_HIGH_CAPACITY_FACTOR > DEFAULT_NORMAL_CAPACITY_FACTOR);
+    }
+
+    #[test]
+    fn test_try_acquire_rejects_once_a_prioritys_budget_is_exhausted() {
+        let pool = SchedPool::with_capacity(1, "test-sched-pool", 1, 2);
+
+        // Normal-priority budget is 1: the first acquire succeeds, the next
+        // is rejected until a release frees the slot back up.
+        assert!(pool.try_acquire(CommandPri::Normal));
+        assert!(!pool.try_acquire(CommandPri::Normal));
+        pool.release(CommandPri::Normal);
+        assert!(pool.try_acquire(CommandPri::Normal));
+
+        // High priority has its own, separate budget of 2 and is unaffected
+        // by normal-priority usage.
+        assert!(pool.try_acquire(CommandPri::High));
+        assert!(pool.try_acquire(CommandPri::High));
+        assert!(!pool.try_acquire(CommandPri::High));
+    }
+}
@@ -0,0 +1,38 @@
+// Copyright 2018 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Messages posted from a worker thread, after a command has finished
+//! snapshot reads or engine writes, back to the `Scheduler` that owns the
+//! command's latches and storage callback.
+
+use crate::storage::lock_manager;
+use crate::storage::txn::process::ProcessResult;
+use crate::storage::txn::Error;
+use crate::storage::kv::Result as EngineResult;
+
+/// Message types for the scheduler event loop.
+pub enum Msg {
+    WaitForLock {
+        cid: u64,
+        start_ts: u64,
+        pr: ProcessResult,
+        lock: lock_manager::Lock,
+        is_first_lock: bool,
+        wait_timeout: i64,
+    },
+    ReadFinished {
+        cid: u64,
+        pr: ProcessResult,
+        tag: &'static str,
+    },
+    WriteFinished {
+        cid: u64,
+        pr: ProcessResult,
+        result: EngineResult<()>,
+        tag: &'static str,
+    },
+    FinishedWithErr {
+        cid: u64,
+        err: Error,
+        tag: &'static str,
+    },
+}